@@ -7,13 +7,227 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use platform::{self, OsIpcReceiver, OsIpcSender, OsIpcOneShotServer};
+use platform::{self, OsIpcReceiver, OsIpcSender, OsIpcOneShotServer, OsOpaqueIpcChannel};
+use platform::{OsIpcSharedMemory, OsIpcSharedMemoryRingBuffer};
+use platform::{OsIpcReceiverSet, OsIpcSelectionResult};
 
+use bincode::{self, SizeLimit};
+use futures::{Async, Future, Poll, Stream};
+use futures::task;
 use serde::json;
+use serde::de;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
 use std::marker::PhantomData;
 use std::mem;
+use std::ops::Deref;
+
+/// A wire codec used to (de)serialize message bodies as they cross a channel.
+///
+/// The JSON backend is kept around because it round-trips through human-readable
+/// text, which is handy when debugging, but it UTF-8-encodes and reallocates
+/// every payload. The binary backend is the default: it emits a compact,
+/// length-prefixed little-endian encoding (fixed/varint integers, a `u64`
+/// length in front of sequences and maps, and a `u32` variant index for enums),
+/// which is the single biggest win on the hot send path.
+pub trait Codec {
+    fn encode<T>(value: &T) -> Result<Vec<u8>,()> where T: Serialize;
+    fn decode<T>(data: &[u8]) -> Result<T,()> where T: Deserialize;
+}
+
+/// The compact binary backend, selected by [`CodecKind::Binary`](enum.CodecKind.html).
+pub enum BinaryCodec {}
+
+/// The JSON backend, retained for debugging and backwards compatibility.
+pub enum JsonCodec {}
+
+impl Codec for BinaryCodec {
+    fn encode<T>(value: &T) -> Result<Vec<u8>,()> where T: Serialize {
+        bincode::serde::serialize(value, SizeLimit::Infinite).map_err(|_| ())
+    }
+    fn decode<T>(data: &[u8]) -> Result<T,()> where T: Deserialize {
+        bincode::serde::deserialize(data).map_err(|_| ())
+    }
+}
+
+impl Codec for JsonCodec {
+    fn encode<T>(value: &T) -> Result<Vec<u8>,()> where T: Serialize {
+        let mut bytes = Vec::with_capacity(4096);
+        {
+            let mut serializer = json::Serializer::new(&mut bytes);
+            try!(value.serialize(&mut serializer).map_err(|_| ()));
+        }
+        Ok(bytes)
+    }
+    fn decode<T>(data: &[u8]) -> Result<T,()> where T: Deserialize {
+        let mut deserializer = match json::Deserializer::new(data.iter().map(|byte| Ok(*byte))) {
+            Ok(deserializer) => deserializer,
+            Err(_) => return Err(()),
+        };
+        Deserialize::deserialize(&mut deserializer).map_err(|_| ())
+    }
+}
+
+/// Selects the wire codec for a channel at runtime, so a given `IpcSender`/
+/// `IpcReceiver` pair can speak binary or JSON without recompiling. Chosen when
+/// the channel is created (see [`channel_with_codec`](fn.channel_with_codec.html));
+/// [`channel`](fn.channel.html) defaults to `Binary`.
+#[derive(Clone, Copy, Debug)]
+pub enum CodecKind {
+    Binary,
+    Json,
+}
+
+impl CodecKind {
+    /// The one-byte tag written into the wire header so the peer's codec can be
+    /// validated before a single body byte reaches the decoder.
+    fn tag(&self) -> u8 {
+        match *self {
+            CodecKind::Binary => 0,
+            CodecKind::Json => 1,
+        }
+    }
+
+    /// Recover a `CodecKind` from a wire-header tag, or `None` if this build does
+    /// not speak that codec.
+    fn from_tag(tag: u8) -> Option<CodecKind> {
+        match tag {
+            0 => Some(CodecKind::Binary),
+            1 => Some(CodecKind::Json),
+            _ => None,
+        }
+    }
+
+    fn encode<T>(&self, value: &T) -> Result<Vec<u8>,()> where T: Serialize {
+        match *self {
+            CodecKind::Binary => BinaryCodec::encode(value),
+            CodecKind::Json => JsonCodec::encode(value),
+        }
+    }
+
+    fn decode<T>(&self, data: &[u8]) -> Result<T,()> where T: Deserialize {
+        match *self {
+            CodecKind::Binary => BinaryCodec::decode(data),
+            CodecKind::Json => JsonCodec::decode(data),
+        }
+    }
+}
+
+/// The codec a channel uses unless one is chosen explicitly.
+const DEFAULT_CODEC: CodecKind = CodecKind::Binary;
+
+/// Magic bytes that open every framed message; a peer speaking an unrelated
+/// protocol will fail this check before we ever hand bytes to the codec.
+const WIRE_MAGIC: [u8; 4] = [b'I', b'P', b'C', b'C'];
+/// The major/minor/patch version of the wire format this build speaks, written
+/// big-endian directly after the magic.
+const WIRE_VERSION: [u8; 3] = [1, 0, 0];
+/// Length of the fixed framing header (`WIRE_MAGIC` + `WIRE_VERSION` + codec tag).
+const WIRE_HEADER_LEN: usize = 8;
+
+/// The error type returned across this crate's public surface.
+#[derive(Debug)]
+pub enum IpcError {
+    /// The underlying OS transport failed (connect, send, or receive).
+    Io,
+    /// The message body could not be serialized.
+    Serialization,
+    /// The message body could not be deserialized.
+    Deserialization,
+    /// A transferred-handle index in the byte stream pointed past the end of
+    /// the transfer list. This is the checked replacement for the old
+    /// `borrow_mut()[index]` panic on untrusted input.
+    OutOfRangeTransferIndex(usize),
+    /// The peer closed the channel.
+    Disconnected,
+    /// The message did not begin with a valid frame — too short to hold a header,
+    /// or not opening with the expected magic. Distinct from `VersionMismatch`:
+    /// this is "not our protocol", not "an older peer of ours".
+    Framing,
+    /// The peer announced an incompatible wire-format version.
+    VersionMismatch(WireVersion),
+    /// The peer framed its message with a codec tag this build does not speak,
+    /// carrying that tag. Surfaced from the header like a version mismatch so an
+    /// unknown encoding can't mis-decode silently.
+    CodecMismatch(u8),
+}
+
+impl fmt::Display for IpcError {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IpcError::Io => write!(formatter, "IPC transport error"),
+            IpcError::Serialization => write!(formatter, "failed to serialize message"),
+            IpcError::Deserialization => write!(formatter, "failed to deserialize message"),
+            IpcError::OutOfRangeTransferIndex(index) =>
+                write!(formatter, "transferred-handle index {} out of range", index),
+            IpcError::Disconnected => write!(formatter, "channel disconnected"),
+            IpcError::Framing => write!(formatter, "malformed message frame"),
+            IpcError::VersionMismatch((major, minor, patch)) =>
+                write!(formatter, "incompatible wire version {}.{}.{}", major, minor, patch),
+            IpcError::CodecMismatch(tag) => match CodecKind::from_tag(tag) {
+                Some(CodecKind::Binary) => write!(formatter, "incompatible wire codec (binary)"),
+                Some(CodecKind::Json) => write!(formatter, "incompatible wire codec (json)"),
+                None => write!(formatter, "unknown wire codec {}", tag),
+            },
+        }
+    }
+}
+
+impl Error for IpcError {
+    fn description(&self) -> &str {
+        match *self {
+            IpcError::Io => "IPC transport error",
+            IpcError::Serialization => "failed to serialize message",
+            IpcError::Deserialization => "failed to deserialize message",
+            IpcError::OutOfRangeTransferIndex(..) => "transferred-handle index out of range",
+            IpcError::Disconnected => "channel disconnected",
+            IpcError::Framing => "malformed message frame",
+            IpcError::VersionMismatch(..) => "incompatible wire version",
+            IpcError::CodecMismatch(..) => "incompatible wire codec",
+        }
+    }
+}
+
+/// The version a peer announced, as `(major, minor, patch)`. Carried by a
+/// framing rejection so the mismatch can be reported rather than swallowed.
+pub type WireVersion = (u8, u8, u8);
+
+/// Prepend the framing header to an encoded message body. The codec tag is
+/// written one byte past the version so a peer speaking a different encoding is
+/// rejected in the header rather than mis-decoding silently in the codec (both
+/// ends can still report version `1.0.0`).
+fn frame(mut body: Vec<u8>, codec: CodecKind) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(WIRE_HEADER_LEN + body.len());
+    framed.extend_from_slice(&WIRE_MAGIC);
+    framed.extend_from_slice(&WIRE_VERSION);
+    framed.push(codec.tag());
+    framed.append(&mut body);
+    framed
+}
+
+/// Validate the framing header and return the codec it was written with together
+/// with the body. The header is checked in the order it is written: a buffer too
+/// short to hold a header or one not opening with the magic yields `Framing`; a
+/// valid magic whose version bytes differ yields a `VersionMismatch` carrying the
+/// peer's reported version; and a codec tag this build does not speak yields a
+/// `CodecMismatch`. Because the codec travels in the header, a receiver decodes
+/// with the sender's actual codec even when it was reconstructed from a
+/// transferred handle and so never learned the original channel's codec.
+fn unframe(data: &[u8]) -> Result<(CodecKind, &[u8]), IpcError> {
+    if data.len() < WIRE_HEADER_LEN || data[0..4] != WIRE_MAGIC {
+        return Err(IpcError::Framing);
+    }
+    let peer_version = (data[4], data[5], data[6]);
+    if peer_version != (WIRE_VERSION[0], WIRE_VERSION[1], WIRE_VERSION[2]) {
+        return Err(IpcError::VersionMismatch(peer_version));
+    }
+    match CodecKind::from_tag(data[7]) {
+        Some(codec) => Ok((codec, &data[WIRE_HEADER_LEN..])),
+        None => Err(IpcError::CodecMismatch(data[7])),
+    }
+}
 
 thread_local! {
     static OS_IPC_SENDERS_FOR_DESERIALIZATION: RefCell<Vec<OsIpcSender>> = RefCell::new(Vec::new())
@@ -21,33 +235,271 @@ thread_local! {
 thread_local! {
     static OS_IPC_SENDERS_FOR_SERIALIZATION: RefCell<Vec<OsIpcSender>> = RefCell::new(Vec::new())
 }
+// Parallel transfer stacks for the *receiving* ends of channels (and, on Unix,
+// bare file descriptors). An `IpcReceiver`/`OwnedFd` serializes by pushing its
+// opaque OS handle here and emitting the index, exactly the way `IpcSender`
+// does, and is reconstructed by looking that index up in the matching list below.
+thread_local! {
+    static OS_IPC_CHANNELS_FOR_DESERIALIZATION: RefCell<Vec<OsOpaqueIpcChannel>> =
+        RefCell::new(Vec::new())
+}
+thread_local! {
+    static OS_IPC_CHANNELS_FOR_SERIALIZATION: RefCell<Vec<OsOpaqueIpcChannel>> =
+        RefCell::new(Vec::new())
+}
+// Out-of-band large values. An `IpcSharedMemory` serializes by handing its
+// mapped region to the OS layer (which transfers the handle alongside the byte
+// buffer) and emitting the index; the bulk bytes never go through the codec.
+thread_local! {
+    static OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION: RefCell<Vec<OsIpcSharedMemory>> =
+        RefCell::new(Vec::new())
+}
+thread_local! {
+    static OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION: RefCell<Vec<OsIpcSharedMemory>> =
+        RefCell::new(Vec::new())
+}
+// Out-of-band channel for the one deserialization failure we need to tell apart
+// from a generic codec error: a transferred-handle index pointing past its
+// transfer list. The `Deserialize` impls can only yield their codec's own
+// `de::Error`, so they record the offending index here and `deserialize_received_data`
+// promotes it to `IpcError::OutOfRangeTransferIndex`.
+thread_local! {
+    static OUT_OF_RANGE_TRANSFER_INDEX: RefCell<Option<usize>> = RefCell::new(None)
+}
+
+/// Record that a transferred-handle index was out of range during the current
+/// deserialization, so the failure can be surfaced as its own `IpcError`.
+fn note_out_of_range_transfer_index(index: usize) {
+    OUT_OF_RANGE_TRANSFER_INDEX.with(|cell| *cell.borrow_mut() = Some(index));
+}
 
-pub fn channel<T>() -> Result<(IpcSender<T>, IpcReceiver<T>),()> where T: Deserialize + Serialize {
+pub fn channel<T>() -> Result<(IpcSender<T>, IpcReceiver<T>),IpcError>
+                       where T: Deserialize + Serialize {
+    channel_with_codec(DEFAULT_CODEC)
+}
+
+/// Like [`channel`](fn.channel.html), but picks the wire codec the sender encodes
+/// with. The receiver reads the codec out of each frame's header, so the choice
+/// only has to be made on the sending end.
+pub fn channel_with_codec<T>(codec: CodecKind)
+                             -> Result<(IpcSender<T>, IpcReceiver<T>),IpcError>
+                             where T: Deserialize + Serialize {
     let (os_sender, os_receiver) = match platform::channel() {
         Ok((os_sender, os_receiver)) => (os_sender, os_receiver),
-        Err(_) => return Err(()),
+        Err(_) => return Err(IpcError::Io),
     };
     let ipc_receiver = IpcReceiver {
-        os_receiver: os_receiver,
+        os_receiver: RefCell::new(Some(os_receiver)),
         phantom: PhantomData,
     };
     let ipc_sender = IpcSender {
         os_sender: os_sender,
+        codec: codec,
         phantom: PhantomData,
     };
     Ok((ipc_sender, ipc_receiver))
 }
 
 pub struct IpcReceiver<T> where T: Deserialize + Serialize {
-    os_receiver: OsIpcReceiver,
+    // Wrapped in `RefCell<Option<_>>` so `serialize` can move the handle out
+    // through `&self` when the receiver is transferred into a message; a bare
+    // `OsIpcReceiver` is not `Clone` and cannot be moved out of a shared ref.
+    os_receiver: RefCell<Option<OsIpcReceiver>>,
     phantom: PhantomData<T>,
 }
 
 impl<T> IpcReceiver<T> where T: Deserialize + Serialize {
-    pub fn recv(&self) -> Result<T,()> {
-        match self.os_receiver.recv() {
-            Ok((data, os_ipc_senders)) => deserialize_received_data(&data[..], os_ipc_senders),
-            Err(_) => Err(()),
+    pub fn recv(&self) -> Result<T,IpcError> {
+        let os_receiver = self.os_receiver.borrow();
+        let os_receiver = os_receiver.as_ref().expect("IpcReceiver used after being transferred");
+        match os_receiver.recv() {
+            Ok((data, os_ipc_senders, os_ipc_channels, os_ipc_shared_memory_regions)) =>
+                deserialize_received_data(&data[..],
+                                          os_ipc_senders,
+                                          os_ipc_channels,
+                                          os_ipc_shared_memory_regions),
+            // A clean peer close and a transport failure both surface as an OS
+            // error here; only the former is a `Disconnected`, the rest are IO.
+            Err(ref err) if err.channel_is_closed() => Err(IpcError::Disconnected),
+            Err(_) => Err(IpcError::Io),
+        }
+    }
+
+    /// Return a future that resolves with the next message, for awaiting this
+    /// receiver inside a futures/tokio runtime. The blocking `recv` above is
+    /// left untouched; the future registers the OS handle's readiness with the
+    /// current task and only runs deserialization once a full message is ready.
+    pub fn recv_async(self) -> IpcReceiverFuture<T> {
+        IpcReceiverFuture {
+            receiver: Some(self),
+        }
+    }
+
+    /// Adapt this receiver into a `Stream` that yields one decoded message per
+    /// frame and ends when the channel closes, so many channels can be folded
+    /// into a single event loop.
+    pub fn into_stream(self) -> IpcReceiverStream<T> {
+        IpcReceiverStream {
+            receiver: self,
+        }
+    }
+}
+
+/// The future returned by [`IpcReceiver::recv_async`](struct.IpcReceiver.html#method.recv_async).
+pub struct IpcReceiverFuture<T> where T: Deserialize + Serialize {
+    receiver: Option<IpcReceiver<T>>,
+}
+
+impl<T> Future for IpcReceiverFuture<T> where T: Deserialize + Serialize {
+    type Item = T;
+    type Error = IpcError;
+
+    fn poll(&mut self) -> Poll<T, IpcError> {
+        let value = {
+            let receiver = self.receiver
+                               .as_ref()
+                               .expect("IpcReceiverFuture polled after completion");
+            let os_receiver = receiver.os_receiver.borrow();
+            let os_receiver =
+                os_receiver.as_ref().expect("IpcReceiver used after being transferred");
+            // Register the task *before* polling readiness. Registering only on
+            // the `NotReady` branch would lose a wakeup if a frame arrived between
+            // the poll and the register and `register_notify` is edge-triggered;
+            // arming first means any readiness after this point schedules us.
+            os_receiver.register_notify(task::current());
+            match try!(os_receiver.poll_recv().map_err(|_| IpcError::Io)) {
+                Async::NotReady => return Ok(Async::NotReady),
+                Async::Ready(None) => return Err(IpcError::Disconnected),
+                Async::Ready(Some((data, senders, channels, shared_memory_regions))) =>
+                    try!(deserialize_received_data(&data[..],
+                                                   senders,
+                                                   channels,
+                                                   shared_memory_regions)),
+            }
+        };
+        self.receiver = None;
+        Ok(Async::Ready(value))
+    }
+}
+
+/// The stream returned by [`IpcReceiver::into_stream`](struct.IpcReceiver.html#method.into_stream).
+pub struct IpcReceiverStream<T> where T: Deserialize + Serialize {
+    receiver: IpcReceiver<T>,
+}
+
+impl<T> Stream for IpcReceiverStream<T> where T: Deserialize + Serialize {
+    type Item = T;
+    type Error = IpcError;
+
+    fn poll(&mut self) -> Poll<Option<T>, IpcError> {
+        let os_receiver = self.receiver.os_receiver.borrow();
+        let os_receiver = os_receiver.as_ref().expect("IpcReceiver used after being transferred");
+        // Arm the wakeup before polling so a frame that arrives between the poll
+        // and the register is not lost against an edge-triggered `register_notify`.
+        os_receiver.register_notify(task::current());
+        match try!(os_receiver.poll_recv().map_err(|_| IpcError::Io)) {
+            Async::NotReady => Ok(Async::NotReady),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::Ready(Some((data, senders, channels, shared_memory_regions))) => {
+                let value = try!(deserialize_received_data(&data[..],
+                                                           senders,
+                                                           channels,
+                                                           shared_memory_regions));
+                Ok(Async::Ready(Some(value)))
+            }
+        }
+    }
+}
+
+impl<T> Serialize for IpcReceiver<T> where T: Deserialize + Serialize {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let index = OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+            let mut os_ipc_channels_for_serialization =
+                os_ipc_channels_for_serialization.borrow_mut();
+            let index = os_ipc_channels_for_serialization.len();
+            let os_receiver = self.os_receiver
+                                  .borrow_mut()
+                                  .take()
+                                  .expect("IpcReceiver transferred more than once");
+            os_ipc_channels_for_serialization.push(os_receiver.consume_into_opaque());
+            index
+        });
+        index.serialize(serializer)
+    }
+}
+
+impl<T> Deserialize for IpcReceiver<T> where T: Deserialize + Serialize {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let os_receiver =
+            OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
+                let mut os_ipc_channels_for_deserialization =
+                    os_ipc_channels_for_deserialization.borrow_mut();
+                os_ipc_channels_for_deserialization.get_mut(index)
+                                                   .map(|channel| channel.to_receiver())
+            });
+        match os_receiver {
+            Some(os_receiver) => Ok(IpcReceiver {
+                os_receiver: RefCell::new(Some(os_receiver)),
+                phantom: PhantomData,
+            }),
+            None => {
+                note_out_of_range_transfer_index(index);
+                Err(de::Error::custom(IpcError::OutOfRangeTransferIndex(index).to_string()))
+            }
+        }
+    }
+}
+
+/// An owned file descriptor that can travel inside a message on Unix, letting a
+/// process hand an arbitrary capability (a pipe, a socket, an open file) to a
+/// peer. Like `IpcReceiver`, it rides the channel-transfer stack rather than the
+/// byte buffer.
+#[cfg(unix)]
+pub struct OwnedFd {
+    fd: platform::OsIpcOwnedFd,
+}
+
+#[cfg(unix)]
+impl OwnedFd {
+    pub fn new(fd: platform::OsIpcOwnedFd) -> OwnedFd {
+        OwnedFd {
+            fd: fd,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Serialize for OwnedFd {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let index = OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+            let mut os_ipc_channels_for_serialization =
+                os_ipc_channels_for_serialization.borrow_mut();
+            let index = os_ipc_channels_for_serialization.len();
+            os_ipc_channels_for_serialization.push(self.fd.clone_into_opaque());
+            index
+        });
+        index.serialize(serializer)
+    }
+}
+
+#[cfg(unix)]
+impl Deserialize for OwnedFd {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let fd = OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
+            let mut os_ipc_channels_for_deserialization =
+                os_ipc_channels_for_deserialization.borrow_mut();
+            os_ipc_channels_for_deserialization.get_mut(index).map(|channel| channel.to_fd())
+        });
+        match fd {
+            Some(fd) => Ok(OwnedFd {
+                fd: fd,
+            }),
+            None => {
+                note_out_of_range_transfer_index(index);
+                Err(de::Error::custom(IpcError::OutOfRangeTransferIndex(index).to_string()))
+            }
         }
     }
 }
@@ -55,33 +507,57 @@ impl<T> IpcReceiver<T> where T: Deserialize + Serialize {
 #[derive(Clone)]
 pub struct IpcSender<T> where T: Serialize {
     os_sender: OsIpcSender,
+    codec: CodecKind,
     phantom: PhantomData<T>,
 }
 
 impl<T> IpcSender<T> where T: Serialize {
-    pub fn connect(name: String) -> Result<IpcSender<T>,()> {
+    pub fn connect(name: String) -> Result<IpcSender<T>,IpcError> {
+        IpcSender::connect_with_codec(name, DEFAULT_CODEC)
+    }
+
+    /// Like [`connect`](#method.connect), but picks the wire codec used to
+    /// encode outgoing messages. The receiving end reads the codec out of each
+    /// frame's header, so it does not have to be told the choice.
+    pub fn connect_with_codec(name: String, codec: CodecKind) -> Result<IpcSender<T>,IpcError> {
         let os_sender = match OsIpcSender::connect(name) {
             Ok(os_sender) => os_sender,
-            Err(_) => return Err(()),
+            Err(_) => return Err(IpcError::Io),
         };
         Ok(IpcSender {
             os_sender: os_sender,
+            codec: codec,
             phantom: PhantomData,
         })
     }
 
-    pub fn send(&self, data: T) -> Result<(),()> {
-        let mut bytes = Vec::with_capacity(4096);
+    pub fn send(&self, data: T) -> Result<(),IpcError> {
         OS_IPC_SENDERS_FOR_SERIALIZATION.with(|os_ipc_senders_for_serialization| {
+        OS_IPC_CHANNELS_FOR_SERIALIZATION.with(|os_ipc_channels_for_serialization| {
+        OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(|os_ipc_shared_memory_regions| {
             let old_os_ipc_senders =
                 mem::replace(&mut *os_ipc_senders_for_serialization.borrow_mut(), Vec::new());
-            let os_ipc_senders = {
-                let mut serializer = json::Serializer::new(&mut bytes);
-                data.serialize(&mut serializer).unwrap();
+            let old_os_ipc_channels =
+                mem::replace(&mut *os_ipc_channels_for_serialization.borrow_mut(), Vec::new());
+            let old_os_ipc_shared_memory_regions =
+                mem::replace(&mut *os_ipc_shared_memory_regions.borrow_mut(), Vec::new());
+            let bytes = self.codec.encode(&data);
+            let os_ipc_senders =
                 mem::replace(&mut *os_ipc_senders_for_serialization.borrow_mut(),
-                             old_os_ipc_senders)
-            };
-            self.os_sender.send(&bytes[..], os_ipc_senders).map_err(|_| ())
+                             old_os_ipc_senders);
+            let os_ipc_channels =
+                mem::replace(&mut *os_ipc_channels_for_serialization.borrow_mut(),
+                             old_os_ipc_channels);
+            let os_ipc_shared_memory_regions =
+                mem::replace(&mut *os_ipc_shared_memory_regions.borrow_mut(),
+                             old_os_ipc_shared_memory_regions);
+            let bytes = frame(try!(bytes.map_err(|_| IpcError::Serialization)), self.codec);
+            self.os_sender.send(&bytes[..],
+                                os_ipc_senders,
+                                os_ipc_channels,
+                                os_ipc_shared_memory_regions).map_err(|_| IpcError::Io)
+        })
+        })
         })
     }
 }
@@ -91,13 +567,23 @@ impl<T> Deserialize for IpcSender<T> where T: Serialize {
         let index: usize = try!(Deserialize::deserialize(deserializer));
         let os_sender =
             OS_IPC_SENDERS_FOR_DESERIALIZATION.with(|os_ipc_senders_for_deserialization| {
-                // FIXME(pcwalton): This could panic. Return some sort of nice error.
-                os_ipc_senders_for_deserialization.borrow_mut()[index].clone()
+                let os_ipc_senders_for_deserialization =
+                    os_ipc_senders_for_deserialization.borrow_mut();
+                // The index comes from an untrusted byte stream, so bounds-check
+                // it rather than panicking inside the deserializer.
+                os_ipc_senders_for_deserialization.get(index).map(|sender| sender.clone())
             });
-        Ok(IpcSender {
-            os_sender: os_sender,
-            phantom: PhantomData,
-        })
+        match os_sender {
+            Some(os_sender) => Ok(IpcSender {
+                os_sender: os_sender,
+                codec: DEFAULT_CODEC,
+                phantom: PhantomData,
+            }),
+            None => {
+                note_out_of_range_transfer_index(index);
+                Err(de::Error::custom(IpcError::OutOfRangeTransferIndex(index).to_string()))
+            }
+        }
     }
 }
 
@@ -120,10 +606,10 @@ pub struct IpcOneShotServer<T> {
 }
 
 impl<T> IpcOneShotServer<T> where T: Deserialize + Serialize {
-    pub fn new() -> Result<(IpcOneShotServer<T>, String),()> {
+    pub fn new() -> Result<(IpcOneShotServer<T>, String),IpcError> {
         let (os_server, name) = match OsIpcOneShotServer::new() {
             Ok(result) => result,
-            Err(_) => return Err(()),
+            Err(_) => return Err(IpcError::Io),
         };
         Ok((IpcOneShotServer {
             os_server: os_server,
@@ -131,33 +617,340 @@ impl<T> IpcOneShotServer<T> where T: Deserialize + Serialize {
         }, name))
     }
 
-    pub fn accept(self) -> Result<(IpcReceiver<T>,T),()> {
-        let (os_receiver, data, os_senders) = match self.os_server.accept() {
-            Ok(result) => result,
-            Err(_) => return Err(()),
-        };
-        let value = try!(deserialize_received_data(&data[..], os_senders));
+    pub fn accept(self) -> Result<(IpcReceiver<T>,T),IpcError> {
+        let (os_receiver, data, os_senders, os_channels, os_shared_memory_regions) =
+            match self.os_server.accept() {
+                Ok(result) => result,
+                Err(_) => return Err(IpcError::Io),
+            };
+        let value = try!(deserialize_received_data(&data[..],
+                                                   os_senders,
+                                                   os_channels,
+                                                   os_shared_memory_regions));
         Ok((IpcReceiver {
-            os_receiver: os_receiver,
+            os_receiver: RefCell::new(Some(os_receiver)),
             phantom: PhantomData,
         }, value))
     }
 }
 
-fn deserialize_received_data<T>(data: &[u8], mut os_ipc_senders: Vec<OsIpcSender>) -> Result<T,()>
+fn deserialize_received_data<T>(data: &[u8],
+                                mut os_ipc_senders: Vec<OsIpcSender>,
+                                mut os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+                                mut os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>)
+                                -> Result<T,IpcError>
                                 where T: Deserialize + Serialize {
     OS_IPC_SENDERS_FOR_DESERIALIZATION.with(|os_ipc_senders_for_deserialization| {
+    OS_IPC_CHANNELS_FOR_DESERIALIZATION.with(|os_ipc_channels_for_deserialization| {
+    OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(|os_ipc_shared_memory_regions_cell| {
         mem::swap(&mut *os_ipc_senders_for_deserialization.borrow_mut(), &mut os_ipc_senders);
-        let mut deserializer = match json::Deserializer::new(data.iter()
-                                                                 .map(|byte| Ok(*byte))) {
-            Ok(deserializer) => deserializer,
-            Err(_) => return Err(()),
-        };
-        let result = match Deserialize::deserialize(&mut deserializer) {
-            Ok(result) => result,
-            Err(_) => return Err(()),
+        mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(), &mut os_ipc_channels);
+        mem::swap(&mut *os_ipc_shared_memory_regions_cell.borrow_mut(),
+                  &mut os_ipc_shared_memory_regions);
+        OUT_OF_RANGE_TRANSFER_INDEX.with(|cell| *cell.borrow_mut() = None);
+        let result = match unframe(data) {
+            Ok((codec, body)) => codec.decode(body).map_err(|_| {
+                // A codec error here may really be an out-of-range transfer
+                // index recorded by one of the handle `Deserialize` impls;
+                // surface that distinctly rather than collapsing it.
+                match OUT_OF_RANGE_TRANSFER_INDEX.with(|cell| cell.borrow_mut().take()) {
+                    Some(index) => IpcError::OutOfRangeTransferIndex(index),
+                    None => IpcError::Deserialization,
+                }
+            }),
+            Err(err) => Err(err),
         };
         mem::swap(&mut *os_ipc_senders_for_deserialization.borrow_mut(), &mut os_ipc_senders);
-        Ok(result)
+        mem::swap(&mut *os_ipc_channels_for_deserialization.borrow_mut(), &mut os_ipc_channels);
+        mem::swap(&mut *os_ipc_shared_memory_regions_cell.borrow_mut(),
+                  &mut os_ipc_shared_memory_regions);
+        result
+    })
     })
+    })
+}
+
+/// An immutable block of shared memory that is moved out-of-band rather than
+/// copied through the codec. Constructing one memcpys the bytes into a freshly
+/// mapped region exactly once; sending it transfers only the region's handle
+/// and length, and `recv` hands back a value that derefs to the mapped bytes
+/// without re-serializing them. Use it for multi-megabyte payloads where the
+/// extra copies of a socket round-trip dominate.
+pub struct IpcSharedMemory {
+    os_shared_memory: OsIpcSharedMemory,
+}
+
+impl Deref for IpcSharedMemory {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.os_shared_memory[..]
+    }
+}
+
+impl IpcSharedMemory {
+    pub fn from_bytes(bytes: &[u8]) -> IpcSharedMemory {
+        IpcSharedMemory {
+            os_shared_memory: OsIpcSharedMemory::from_bytes(bytes),
+        }
+    }
+}
+
+impl Serialize for IpcSharedMemory {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(),S::Error> where S: Serializer {
+        let index = OS_IPC_SHARED_MEMORY_REGIONS_FOR_SERIALIZATION.with(|regions| {
+            let mut regions = regions.borrow_mut();
+            let index = regions.len();
+            regions.push(self.os_shared_memory.clone());
+            index
+        });
+        index.serialize(serializer)
+    }
+}
+
+impl Deserialize for IpcSharedMemory {
+    fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error> where D: Deserializer {
+        let index: usize = try!(Deserialize::deserialize(deserializer));
+        let os_shared_memory =
+            OS_IPC_SHARED_MEMORY_REGIONS_FOR_DESERIALIZATION.with(|regions| {
+                regions.borrow_mut().get(index).map(|region| region.clone())
+            });
+        match os_shared_memory {
+            Some(os_shared_memory) => Ok(IpcSharedMemory {
+                os_shared_memory: os_shared_memory,
+            }),
+            None => {
+                note_out_of_range_transfer_index(index);
+                Err(de::Error::custom(IpcError::OutOfRangeTransferIndex(index).to_string()))
+            }
+        }
+    }
+}
+
+/// A streaming SPSC channel whose bulk data lives in a shared-memory ring
+/// buffer. The segment header holds read/write offsets that are advanced
+/// atomically; the sender writes a length-prefixed frame into the ring and
+/// wakes the peer over its paired `IpcSender`, and the receiver reads the frame
+/// in place. The socket carries only the wakeup, never the payload.
+pub struct IpcSharedMemoryRingSender {
+    ring: OsIpcSharedMemoryRingBuffer,
+    wakeup: IpcSender<()>,
+}
+
+pub struct IpcSharedMemoryRingReceiver {
+    ring: OsIpcSharedMemoryRingBuffer,
+    wakeup: IpcReceiver<()>,
+}
+
+/// Allocate a shared ring buffer of `capacity` bytes and return its two ends.
+pub fn shared_memory_ring(capacity: usize)
+        -> Result<(IpcSharedMemoryRingSender, IpcSharedMemoryRingReceiver),IpcError> {
+    let ring = try!(OsIpcSharedMemoryRingBuffer::new(capacity).map_err(|_| IpcError::Io));
+    let (wakeup_sender, wakeup_receiver) = try!(channel());
+    Ok((IpcSharedMemoryRingSender {
+        ring: ring.clone(),
+        wakeup: wakeup_sender,
+    }, IpcSharedMemoryRingReceiver {
+        ring: ring,
+        wakeup: wakeup_receiver,
+    }))
+}
+
+impl IpcSharedMemoryRingSender {
+    /// Write a single length-prefixed frame into the ring and wake the receiver.
+    pub fn send(&self, frame: &[u8]) -> Result<(),IpcError> {
+        try!(self.ring.write_frame(frame).map_err(|_| IpcError::Io));
+        self.wakeup.send(())
+    }
+}
+
+impl IpcSharedMemoryRingReceiver {
+    /// Block until a frame is available and return a view borrowing it directly
+    /// out of the mapped ring. No bytes are copied; the read offset is advanced
+    /// when the returned guard is dropped, freeing the slot for the sender.
+    pub fn recv(&self) -> Result<RingFrame,IpcError> {
+        try!(self.wakeup.recv());
+        let frame = try!(self.ring.read_frame_in_place().map_err(|_| IpcError::Io));
+        Ok(RingFrame {
+            ring: &self.ring,
+            frame: frame,
+        })
+    }
+}
+
+/// A borrowed view of one frame read in place from a shared-memory ring buffer.
+/// Derefs to the frame bytes; dropping it advances the ring's read offset so the
+/// slot can be reused by the sender.
+pub struct RingFrame<'a> {
+    ring: &'a OsIpcSharedMemoryRingBuffer,
+    frame: &'a [u8],
+}
+
+impl<'a> Deref for RingFrame<'a> {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.frame
+    }
+}
+
+impl<'a> Drop for RingFrame<'a> {
+    fn drop(&mut self) {
+        self.ring.advance_read(self.frame.len());
+    }
+}
+
+/// A set of receivers that can be blocked on together, so a router can service
+/// many channels from one thread instead of dedicating a thread per channel.
+/// Each receiver is keyed by a `u64` id handed back when it is added, and new
+/// receivers — including ones that just arrived as transferred handles inside a
+/// message — can be added while the set is live.
+pub struct IpcReceiverSet {
+    os_receiver_set: OsIpcReceiverSet,
+}
+
+/// One event produced by [`IpcReceiverSet::select`](struct.IpcReceiverSet.html#method.select),
+/// distinguishing a delivered message from a channel that has closed.
+pub enum IpcSelectionResult {
+    /// A message arrived on the receiver with this id; decode it on demand with
+    /// [`OpaqueIpcMessage::to`](struct.OpaqueIpcMessage.html#method.to).
+    MessageReceived(u64, OpaqueIpcMessage),
+    /// The receiver with this id has been closed by its peer.
+    ChannelClosed(u64),
+}
+
+/// A received but not-yet-decoded message, holding the raw bytes alongside the
+/// OS handles transferred with it. Deferring the decode keeps `select` cheap
+/// when a router only needs to dispatch on the channel id.
+pub struct OpaqueIpcMessage {
+    data: Vec<u8>,
+    os_ipc_senders: Vec<OsIpcSender>,
+    os_ipc_channels: Vec<OsOpaqueIpcChannel>,
+    os_ipc_shared_memory_regions: Vec<OsIpcSharedMemory>,
+}
+
+impl OpaqueIpcMessage {
+    /// Decode the message body as a `T`, consuming the transferred handles. The
+    /// codec is read from the message's own frame header, so the set does not
+    /// have to track it per receiver.
+    pub fn to<T>(self) -> Result<T,IpcError> where T: Deserialize + Serialize {
+        deserialize_received_data(&self.data[..],
+                                  self.os_ipc_senders,
+                                  self.os_ipc_channels,
+                                  self.os_ipc_shared_memory_regions)
+    }
+}
+
+impl IpcReceiverSet {
+    pub fn new() -> Result<IpcReceiverSet,IpcError> {
+        let os_receiver_set = match OsIpcReceiverSet::new() {
+            Ok(os_receiver_set) => os_receiver_set,
+            Err(_) => return Err(IpcError::Io),
+        };
+        Ok(IpcReceiverSet {
+            os_receiver_set: os_receiver_set,
+        })
+    }
+
+    /// Add a receiver to the set and return the id later events will report it
+    /// under. The receiver's type parameter is erased here; decode with the
+    /// concrete type via [`OpaqueIpcMessage::to`](struct.OpaqueIpcMessage.html#method.to).
+    pub fn add<T>(&mut self, receiver: IpcReceiver<T>) -> Result<u64,IpcError>
+                  where T: Deserialize + Serialize {
+        let os_receiver = receiver.os_receiver
+                                  .into_inner()
+                                  .expect("IpcReceiver added after being transferred");
+        self.os_receiver_set.add(os_receiver).map_err(|_| IpcError::Io)
+    }
+
+    /// Block until at least one member has an event, then return all events
+    /// that are ready.
+    pub fn select(&mut self) -> Result<Vec<IpcSelectionResult>,IpcError> {
+        let results = try!(self.os_receiver_set.select().map_err(|_| IpcError::Io));
+        Ok(results.into_iter().map(|result| {
+            match result {
+                OsIpcSelectionResult::DataReceived(id,
+                                                   data,
+                                                   os_ipc_senders,
+                                                   os_ipc_channels,
+                                                   os_ipc_shared_memory_regions) => {
+                    IpcSelectionResult::MessageReceived(id, OpaqueIpcMessage {
+                        data: data,
+                        os_ipc_senders: os_ipc_senders,
+                        os_ipc_channels: os_ipc_channels,
+                        os_ipc_shared_memory_regions: os_ipc_shared_memory_regions,
+                    })
+                }
+                OsIpcSelectionResult::ChannelClosed(id) => {
+                    IpcSelectionResult::ChannelClosed(id)
+                }
+            }
+        }).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trips_through_unframe() {
+        let framed = frame(vec![1, 2, 3], CodecKind::Json);
+        let (codec, body) = unframe(&framed[..]).unwrap();
+        assert_eq!(codec.tag(), CodecKind::Json.tag());
+        assert_eq!(body, &[1, 2, 3]);
+    }
+
+    #[test]
+    fn unframe_rejects_a_short_buffer_as_framing() {
+        match unframe(&[b'I', b'P', b'C']) {
+            Err(IpcError::Framing) => {}
+            other => panic!("expected Framing, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unframe_rejects_a_bad_magic_as_framing() {
+        let mut framed = frame(vec![0], CodecKind::Binary);
+        framed[0] = b'X';
+        match unframe(&framed[..]) {
+            Err(IpcError::Framing) => {}
+            other => panic!("expected Framing, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unframe_reports_the_peer_version_on_mismatch() {
+        let mut framed = frame(vec![0], CodecKind::Binary);
+        framed[4] = WIRE_VERSION[0] + 1;
+        match unframe(&framed[..]) {
+            Err(IpcError::VersionMismatch(version)) =>
+                assert_eq!(version, (WIRE_VERSION[0] + 1, WIRE_VERSION[1], WIRE_VERSION[2])),
+            other => panic!("expected VersionMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unframe_rejects_an_unknown_codec_tag() {
+        let mut framed = frame(vec![0], CodecKind::Binary);
+        framed[7] = 0xff;
+        match unframe(&framed[..]) {
+            Err(IpcError::CodecMismatch(tag)) => assert_eq!(tag, 0xff),
+            other => panic!("expected CodecMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn an_out_of_range_transfer_index_is_reported_not_panicked() {
+        // A body that decodes to a transferred sender whose index points past an
+        // empty transfer list must surface `OutOfRangeTransferIndex`, exercising
+        // the checked lookup that replaced the old `borrow_mut()[index]` panic.
+        let body = BinaryCodec::encode(&7usize).unwrap();
+        let framed = frame(body, CodecKind::Binary);
+        let result: Result<IpcSender<i32>, IpcError> =
+            deserialize_received_data(&framed[..], Vec::new(), Vec::new(), Vec::new());
+        match result {
+            Err(IpcError::OutOfRangeTransferIndex(index)) => assert_eq!(index, 7),
+            Err(other) => panic!("expected OutOfRangeTransferIndex, got {:?}", other),
+            Ok(_) => panic!("expected OutOfRangeTransferIndex, got a sender"),
+        }
+    }
 }